@@ -0,0 +1,40 @@
+//! Additions to the server configuration surface for the QUIC-tunneled UDP
+//! transport (`relay::udprelay::redir_local`).
+//!
+//! `ServerConfig` itself -- along with its `addr()`, `method()`, `key()`, and
+//! the rest of the accessors `redir_local.rs` already calls -- lives in the
+//! rest of this crate's `config` module, which this snapshot doesn't carry
+//! (same as `context::Context` and the `relay::{loadbalancing, socks5, sys,
+//! utils}` modules it also depends on). This file adds only the pieces that
+//! are new: the transport selector enum and the two accessors that read it
+//! off `ServerConfig`.
+
+/// How a UDP association's traffic is carried to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpTransport {
+    /// Plain UDP, one packet per datagram -- the original behavior.
+    Native,
+    /// Tunneled over a QUIC stream, multiplexed onto one connection per server.
+    Quic,
+}
+
+impl Default for UdpTransport {
+    fn default() -> UdpTransport {
+        UdpTransport::Native
+    }
+}
+
+impl ServerConfig {
+    /// Which transport this server's UDP associations should use.
+    pub fn udp_transport(&self) -> UdpTransport {
+        self.udp_transport
+    }
+
+    /// Whether the QUIC transport should skip server certificate
+    /// verification. Only meaningful when `udp_transport()` is
+    /// `UdpTransport::Quic`; opt-in, for servers that terminate QUIC with a
+    /// self-signed certificate.
+    pub fn udp_quic_skip_cert_verify(&self) -> bool {
+        self.udp_quic_skip_cert_verify
+    }
+}
@@ -1,25 +1,27 @@
 //! UDP relay local server
 
 use std::{
+    collections::HashMap,
     io::{self, Cursor, Read},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex, Weak},
     time::Duration,
 };
 
 use bytes::BytesMut;
-use futures::{future, FutureExt};
 use log::{debug, error, info, trace};
-use lru_time_cache::{Entry, LruCache};
+use lru_time_cache::LruCache;
 use tokio::{
     self,
-    net::udp::{RecvHalf, SendHalf},
-    sync::{mpsc, oneshot, Mutex},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::udp::{OwnedRecvHalf, OwnedSendHalf},
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
     time,
 };
 
 use crate::{
-    config::{ServerAddr, ServerConfig},
+    config::{ServerAddr, ServerConfig, UdpTransport},
     context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
@@ -36,12 +38,328 @@ use super::{
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
+/// ALPN identifier advertised/accepted for the QUIC UDP transport
+const QUIC_ALPN: &[u8] = b"ss-udp";
+
+/// Upper bound on bidirectional streams a single QUIC connection will accept
+/// from the server. It does not limit the streams this side opens: each
+/// association opens one, and how many of those a connection can carry at
+/// once is bounded by the server's own setting.
+const QUIC_MAX_CONCURRENT_BIDI_STREAMS: u32 = 1024;
+
+/// How many idle QUIC connections to servers we keep around before the LRU
+/// cache starts closing the least-recently-used one
+const QUIC_CONN_CACHE_CAPACITY: usize = 64;
+
+/// Upper bound on live UDP associations. Past this, the LRU cache evicts the
+/// least-recently-used entry instead of growing forever, so a flood of
+/// distinct `(src, dst)` pairs can't exhaust file descriptors.
+const MAX_ASSOCIATIONS_CAPACITY: usize = 1024;
+
+/// The remote -> local task lingers for `udp_timeout / LINGER_DIVISOR` after
+/// an association starts draining, so a response already in flight still
+/// reaches the client instead of being dropped on the floor.
+const LINGER_DIVISOR: u32 = 4;
+
+/// Grace period added on top of the linger window before the `Drop`-driven
+/// safety net force-aborts a task that didn't end on its own (e.g. wedged on
+/// a DNS lookup)
+const ABORT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The association cleanup ticker runs every `udp_timeout / CLEANUP_TICK_DIVISOR`,
+/// so an expired entry is purged well before it could have lingered a full
+/// `udp_timeout` past expiry
+const CLEANUP_TICK_DIVISOR: u32 = 2;
+
+/// Cooperative close state shared between an association's two tasks over a
+/// `watch` channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AssocState {
+    /// Business as usual
+    Active,
+    /// Stop accepting new work, but let in-flight packets finish
+    Draining,
+    /// The linger window has elapsed; tasks should end now
+    Closed,
+}
+
 fn cache_key(src: &SocketAddr, dst: &SocketAddr) -> String {
     format!("{}-{}", src, dst)
 }
 
-// Drop the oneshot::Sender<()> will trigger local <- remote task to finish
-struct UdpAssociationWatcher(oneshot::Sender<()>);
+/// Accepts every server certificate without checking it.
+///
+/// Only reachable when a server's config opts in (`udp_quic_skip_cert_verify`), for
+/// talking to servers that terminate QUIC with a self-signed certificate.
+struct InsecureCertVerifier;
+
+impl rustls::client::ServerCertVerifier for InsecureCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn build_quic_client_config(skip_cert_verify: bool) -> quinn::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder().with_safe_defaults();
+
+    let mut crypto = if skip_cert_verify {
+        crypto
+            .with_custom_certificate_verifier(Arc::new(InsecureCertVerifier))
+            .with_no_client_auth()
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+        crypto.with_root_certificates(roots).with_no_client_auth()
+    };
+
+    crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_bidi_streams(quinn::VarInt::from_u32(QUIC_MAX_CONCURRENT_BIDI_STREAMS));
+
+    let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+    client_config.transport_config(Arc::new(transport));
+    client_config
+}
+
+/// Caches one `quinn::Connection` per server address so associations sharing
+/// a server reuse the same connection instead of each opening their own
+type QuicConnCache = LruCache<SocketAddr, quinn::Connection>;
+
+/// Process-wide QUIC client state: a single endpoint plus the connection
+/// cache keyed by server address
+struct QuicContext {
+    endpoint: quinn::Endpoint,
+    conns: Mutex<QuicConnCache>,
+}
+
+type SharedQuicContext = Arc<QuicContext>;
+
+fn init_quic_context() -> io::Result<SharedQuicContext> {
+    let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let endpoint = quinn::Endpoint::client(local_addr)?;
+
+    Ok(Arc::new(QuicContext {
+        endpoint,
+        conns: Mutex::new(LruCache::with_capacity(QUIC_CONN_CACHE_CAPACITY)),
+    }))
+}
+
+/// Get a cached connection to `remote_addr`, or dial a fresh one and cache it
+async fn get_or_connect_quic(
+    quic_ctx: &SharedQuicContext,
+    remote_addr: SocketAddr,
+    skip_cert_verify: bool,
+) -> io::Result<quinn::Connection> {
+    {
+        let mut conns = quic_ctx.conns.lock().await;
+        if let Some(conn) = conns.get(&remote_addr) {
+            if conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+            let _ = conns.remove(&remote_addr);
+        }
+    }
+
+    let client_config = build_quic_client_config(skip_cert_verify);
+    let connecting = quic_ctx
+        .endpoint
+        .connect_with(client_config, remote_addr, "ss-udp")
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let new_conn = connecting.await.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+    let mut conns = quic_ctx.conns.lock().await;
+    conns.insert(remote_addr, new_conn.clone());
+    Ok(new_conn)
+}
+
+/// How many distinct servers' shared remote sockets we keep around before the
+/// LRU cache starts closing the least-recently-used one
+const REMOTE_SOCKET_CACHE_CAPACITY: usize = 256;
+
+/// One client registered in a `RemoteSocketContext`'s reverse map: who a reply
+/// reported as coming from `dst_addr` should be delivered to.
+#[derive(Clone)]
+struct ReverseEntry {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    local_udp: Arc<Mutex<TProxyUdpSocket>>,
+}
+
+/// One real UDP socket shared by every native-transport association talking
+/// to the same server, plus the reverse map `dispatch_remote_replies` uses to
+/// route a reply back to the right client
+struct RemoteSocketContext {
+    sender: Mutex<OwnedSendHalf>,
+    // Keyed by `(src_addr, dst_addr)`, the same identity as an association's
+    // cache_key, so two clients relaying through the same destination get
+    // independent entries instead of one silently overwriting the other.
+    //
+    // Looked up on the way back by comparing a reply's reported source
+    // `Address` against each entry's `dst_addr`; if several clients share a
+    // `dst_addr` the reply is delivered to all of them, since the shared
+    // socket gives us no other way to tell which one it was actually meant
+    // for. A reply reported from an address that was never registered as a
+    // `dst_addr` still can't be matched to anyone and is dropped.
+    reverse: Mutex<HashMap<(SocketAddr, SocketAddr), ReverseEntry>>,
+
+    // Join handle of the `dispatch_remote_replies` task spawned for this
+    // socket. `Drop` aborts it, so once the last reference to this context
+    // goes away (cache eviction with no association still using it) the
+    // dispatcher and the socket it reads from actually go away too, instead
+    // of running forever on a socket nothing can reach any more.
+    dispatcher: StdMutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for RemoteSocketContext {
+    fn drop(&mut self) {
+        if let Some(task) = self.dispatcher.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+type SharedRemoteSocketContext = Arc<RemoteSocketContext>;
+
+/// Process-wide cache of `RemoteSocketContext`s, keyed by server address
+struct RemoteSocketCache {
+    contexts: LruCache<SocketAddr, SharedRemoteSocketContext>,
+}
+
+type SharedRemoteSocketCache = Arc<Mutex<RemoteSocketCache>>;
+
+fn init_remote_socket_cache() -> SharedRemoteSocketCache {
+    Arc::new(Mutex::new(RemoteSocketCache {
+        contexts: LruCache::with_capacity(REMOTE_SOCKET_CACHE_CAPACITY),
+    }))
+}
+
+/// Get the shared socket context for `remote_addr`, creating it (and its
+/// dispatcher task) on first use
+async fn get_or_create_remote_socket(
+    cache: &SharedRemoteSocketCache,
+    remote_addr: SocketAddr,
+    server: SharedPlainServerStatistic,
+    assoc_map: SharedAssocMap,
+) -> io::Result<SharedRemoteSocketContext> {
+    let mut cache = cache.lock().await;
+    if let Some(ctx) = cache.contexts.get(&remote_addr) {
+        return Ok(ctx.clone());
+    }
+
+    let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let remote_udp = create_udp_socket(&local_addr).await?;
+    // `into_split` (tokio 1.x), not the borrowing `split`: the receive half is
+    // moved into the dispatcher task below, well past this function's return.
+    let (receiver, sender) = remote_udp.into_split();
+
+    let ctx = Arc::new(RemoteSocketContext {
+        sender: Mutex::new(sender),
+        reverse: Mutex::new(HashMap::new()),
+        dispatcher: StdMutex::new(None),
+    });
+
+    let dispatcher = tokio::spawn(UdpAssociation::dispatch_remote_replies(
+        remote_addr,
+        receiver,
+        Arc::downgrade(&ctx),
+        assoc_map,
+        server,
+    ));
+    *ctx.dispatcher.lock().unwrap() = Some(dispatcher);
+
+    cache.contexts.insert(remote_addr, ctx.clone());
+    Ok(ctx)
+}
+
+// Owns the local -> remote and local <- remote tasks of an association, plus
+// the `watch` channel used to ask them to close cooperatively.
+//
+// Dropping it (on cache eviction or server stop) moves the pair into
+// `Draining`: the local -> remote task drains whatever is left in its mpsc
+// queue and stops on its own once the sender side is gone, while the local <-
+// remote task keeps delivering late responses for up to `linger` before it
+// ends itself. A background task force-aborts both as a safety net if either
+// is still running well after that window (e.g. wedged on a DNS lookup).
+struct UdpAssociationWatcher {
+    l2r_task: Option<JoinHandle<()>>,
+    r2l_task: Option<JoinHandle<()>>,
+    // Only `Some` for the QUIC transport, whose r2l_task `select!`s on it to
+    // know when the linger window is up. The native transport has no
+    // per-association r2l_task to drive (replies are demultiplexed by the
+    // shared socket's dispatcher task instead), so there's nothing to observe
+    // this and it stays `None` there.
+    state_tx: Option<watch::Sender<AssocState>>,
+    linger: Duration,
+
+    // Only set for the native transport, which no longer owns a remote socket
+    // of its own: these unregister this association from the shared socket's
+    // reverse map so the dispatcher stops routing replies to it.
+    remote_ctx: Option<SharedRemoteSocketContext>,
+    reverse_key: Option<(SocketAddr, SocketAddr)>,
+    // The `local_udp` this association registered itself under `reverse_key`
+    // with. Identifies *this* registration, as opposed to whatever another
+    // association of the same (src, dst) pair might have re-registered under
+    // the same key while this one was still lingering -- see the `Arc::ptr_eq`
+    // check in `Drop`.
+    reverse_identity: Option<Arc<Mutex<TProxyUdpSocket>>>,
+}
+
+impl Drop for UdpAssociationWatcher {
+    fn drop(&mut self) {
+        if let Some(state_tx) = &self.state_tx {
+            let _ = state_tx.send(AssocState::Draining);
+        }
+
+        let state_tx = self.state_tx.clone();
+        let linger = self.linger;
+        let l2r_task = self.l2r_task.take();
+        let r2l_task = self.r2l_task.take();
+        let remote_ctx = self.remote_ctx.take();
+        let reverse_key = self.reverse_key.take();
+        let reverse_identity = self.reverse_identity.take();
+
+        tokio::spawn(async move {
+            time::sleep(linger).await;
+            if let Some(state_tx) = &state_tx {
+                let _ = state_tx.send(AssocState::Closed);
+            }
+
+            if let (Some(ctx), Some(key), Some(identity)) = (remote_ctx, reverse_key, reverse_identity) {
+                let mut reverse = ctx.reverse.lock().await;
+                // Only remove the entry if it's still the one *this*
+                // association registered: a new association for the same
+                // (src, dst) pair may have replaced it while this one was
+                // lingering (e.g. a repeated DNS query to the same resolver),
+                // and that entry must not be deleted out from under it.
+                let is_ours = reverse.get(&key).map_or(false, |e| Arc::ptr_eq(&e.local_udp, &identity));
+                if is_ours {
+                    reverse.remove(&key);
+                }
+            }
+
+            // Safety net: give the tasks a brief moment to observe `Closed` and
+            // end themselves, then abort anything still running.
+            time::sleep(ABORT_GRACE_PERIOD).await;
+            if let Some(t) = l2r_task {
+                t.abort();
+            }
+            if let Some(t) = r2l_task {
+                t.abort();
+            }
+        });
+    }
+}
 
 // Represent a UDP association
 #[derive(Clone)]
@@ -61,50 +379,95 @@ impl UdpAssociation {
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
         assoc_map: SharedAssocMap,
+        quic_ctx: SharedQuicContext,
+        remote_socket_cache: SharedRemoteSocketCache,
+    ) -> io::Result<UdpAssociation> {
+        match server.server_config().udp_transport() {
+            UdpTransport::Native => {
+                UdpAssociation::associate_native(server, src_addr, dst_addr, assoc_map, remote_socket_cache).await
+            }
+            UdpTransport::Quic => {
+                UdpAssociation::associate_quic(server, src_addr, dst_addr, assoc_map, quic_ctx).await
+            }
+        }
+    }
+
+    /// Create an association that relays each datagram to the server through
+    /// a socket shared by every association talking to that same server,
+    /// demultiplexing replies by the address they're reported as coming from
+    async fn associate_native(
+        server: SharedPlainServerStatistic,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        assoc_map: SharedAssocMap,
+        remote_socket_cache: SharedRemoteSocketCache,
     ) -> io::Result<UdpAssociation> {
-        // Create a socket for receiving packets
-        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-        let remote_udp = create_udp_socket(&local_addr).await?;
+        let svr_cfg = server.server_config();
+
+        let remote_addr = match svr_cfg.addr() {
+            ServerAddr::SocketAddr(ref a) => *a,
+            ServerAddr::DomainName(ref dname, port) => {
+                lookup_then!(server.context(), dname, *port, false, |addr| Ok(addr) as io::Result<SocketAddr>)
+                    .map(|(_, a)| a)?
+            }
+        };
+
+        let remote_ctx =
+            get_or_create_remote_socket(&remote_socket_cache, remote_addr, server.clone(), assoc_map.clone()).await?;
 
-        let local_addr = remote_udp.local_addr().expect("Could not determine port bound to");
         debug!(
-            "Created UDP Association for {} from {} -> {}",
-            src_addr, local_addr, dst_addr
+            "Created UDP Association for {} -> {} via shared socket to {}",
+            src_addr, dst_addr, remote_addr
+        );
+
+        // Create a socket for sending packets back, spoofed as dst_addr so the
+        // client sees the reply as coming from the destination it asked for.
+        // Shared with the dispatcher, which is the one writing replies back.
+        let local_udp = Arc::new(Mutex::new(TProxyUdpSocket::bind(&dst_addr)?));
+
+        // Register this association so the dispatcher can route replies "from"
+        // dst_addr back to src_addr. Keyed by the full (src, dst) pair so a
+        // second client relaying through the same dst_addr doesn't evict this
+        // entry.
+        let reverse_key = (src_addr, dst_addr);
+        let reverse_identity = local_udp.clone();
+        remote_ctx.reverse.lock().await.insert(
+            reverse_key,
+            ReverseEntry {
+                src_addr,
+                dst_addr,
+                local_udp,
+            },
         );
 
         // Create a channel for sending packets to remote
         // FIXME: Channel size 1024?
         let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
 
-        // Create a watcher for local <- remote task
-        let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
-
-        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx));
-
-        // Splits socket into sender and receiver
-        let (mut receiver, mut sender) = remote_udp.split();
-
-        // Create a socket for sending packets back
-        let mut local_udp = TProxyUdpSocket::bind(&dst_addr)?;
-
         let timeout = server.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
-
-        {
-            // local -> remote
-
+        let linger = timeout / LINGER_DIVISOR;
+
+        // local -> remote, through the shared per-server socket
+        //
+        // No close state to watch on this path: dropping the association drops
+        // `tx`, so this loop already drains whatever is left in the channel and
+        // ends on its own once it empties.
+        let l2r_task = {
             let server = server.clone();
+            let remote_ctx = remote_ctx.clone();
             tokio::spawn(async move {
                 let svr_cfg = server.server_config();
                 let context = server.context();
-                let dst_addr = Address::from(dst_addr);
+                let dst_address = Address::from(dst_addr);
 
                 while let Some(pkt) = rx.recv().await {
                     // pkt is already a raw packet, so just send it
-                    let res = UdpAssociation::relay_l2r(
+                    let res = UdpAssociation::relay_l2r_shared(
                         context,
                         &src_addr,
-                        &dst_addr,
-                        &mut sender,
+                        &dst_address,
+                        &remote_ctx,
+                        remote_addr,
                         &pkt[..],
                         timeout,
                         svr_cfg,
@@ -119,60 +482,166 @@ impl UdpAssociation {
                 }
 
                 debug!("UDP REDIR {} -> {} finished", src_addr, dst_addr);
-            });
-        }
+            })
+        };
 
-        // local <- remote
-        tokio::spawn(async move {
-            let svr_cfg = server.server_config();
-            let context = server.context();
+        Ok(UdpAssociation {
+            tx,
+            watcher: Arc::new(UdpAssociationWatcher {
+                l2r_task: Some(l2r_task),
+                r2l_task: None,
+                state_tx: None,
+                linger,
+                remote_ctx: Some(remote_ctx),
+                reverse_key: Some(reverse_key),
+                reverse_identity: Some(reverse_identity),
+            }),
+        })
+    }
+
+    /// Create an association that tunnels the `(src, dst)` conversation over
+    /// a single bidirectional stream of a cached `quinn::Connection` to the
+    /// server, instead of a raw UDP socket
+    async fn associate_quic(
+        server: SharedPlainServerStatistic,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        assoc_map: SharedAssocMap,
+        quic_ctx: SharedQuicContext,
+    ) -> io::Result<UdpAssociation> {
+        let svr_cfg = server.server_config();
+        let skip_cert_verify = svr_cfg.udp_quic_skip_cert_verify();
+
+        let remote_addr = match svr_cfg.addr() {
+            ServerAddr::SocketAddr(ref a) => *a,
+            ServerAddr::DomainName(ref dname, port) => {
+                lookup_then!(server.context(), dname, *port, false, |addr| Ok(addr) as io::Result<SocketAddr>)
+                    .map(|(_, a)| a)?
+            }
+        };
+
+        let conn = get_or_connect_quic(&quic_ctx, remote_addr, skip_cert_verify).await?;
+        debug!("Opening QUIC stream for UDP Association {} -> {}", src_addr, dst_addr);
+
+        let (quic_send, quic_recv) = conn.open_bi().await.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        // Create a socket for sending packets back
+        let mut local_udp = TProxyUdpSocket::bind(&dst_addr)?;
+
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
+
+        let timeout = server.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let linger = timeout / LINGER_DIVISOR;
+
+        let (state_tx, mut state_rx) = watch::channel(AssocState::Active);
+
+        // local -> remote, over the QUIC stream
+        //
+        // No need to watch `state_rx` here: dropping the association drops `tx`
+        // together with `state_tx`, so this loop already drains whatever is left
+        // in the channel and ends on its own once it empties.
+        let l2r_task = {
+            let server = server.clone();
+            let mut quic_send = quic_send;
+            tokio::spawn(async move {
+                let svr_cfg = server.server_config();
+                let context = server.context();
+                let dst_addr = Address::from(dst_addr);
 
-            let transfer_fut = async move {
-                loop {
-                    // Read and send back to source
+                while let Some(pkt) = rx.recv().await {
                     let res =
-                        UdpAssociation::relay_r2l(context, &src_addr, &mut receiver, &mut local_udp, svr_cfg).await;
+                        UdpAssociation::relay_l2r_quic(context, &src_addr, &dst_addr, &mut quic_send, &pkt[..], svr_cfg)
+                            .await;
 
                     if let Err(err) = res {
-                        error!("failed to receive packet, {} <- {}, error: {}", src_addr, dst_addr, err);
-
-                        // FIXME: Don't break, or if you can find a way to drop the UdpAssociation
-                        // break;
+                        error!(
+                            "failed to send packet over QUIC {} -> {}, error: {}",
+                            src_addr, dst_addr, err
+                        );
                     }
+                }
 
-                    let cache_key = cache_key(&src_addr, &dst_addr);
-                    {
-                        let mut amap = assoc_map.lock().await;
+                debug!("UDP REDIR (QUIC) {} -> {} finished", src_addr, dst_addr);
+            })
+        };
 
-                        // Check or update expire time
-                        let _ = amap.get(&cache_key);
+        // local <- remote, over the QUIC stream
+        let r2l_task = tokio::spawn(async move {
+            let svr_cfg = server.server_config();
+            let context = server.context();
+            let mut quic_recv = quic_recv;
+
+            loop {
+                tokio::select! {
+                    res = UdpAssociation::relay_r2l_quic(context, &src_addr, &mut quic_recv, &mut local_udp, svr_cfg) => {
+                        match res {
+                            Ok(()) => {
+                                // Same reasoning as the native dispatcher: a receive-only
+                                // flow has nothing on the local -> remote path to keep
+                                // refreshing expiry, so do it here on every reply too.
+                                let cache_key = cache_key(&src_addr, &dst_addr);
+                                let mut amap = assoc_map.lock().await;
+                                let _ = amap.get(&cache_key);
+                            }
+                            Err(err) => {
+                                error!(
+                                    "failed to receive packet over QUIC, {} <- {}, error: {}",
+                                    src_addr, dst_addr, err
+                                );
+
+                                // A persistently failing stream would otherwise spin this loop forever;
+                                // let the task end and the association ride out its normal eviction.
+                                break;
+                            }
+                        }
+                    }
+                    _ = state_rx.changed() => {
+                        // `Draining`: keep delivering in-flight responses. `Closed`: the
+                        // linger window (`linger`) has elapsed, time to stop.
+                        if *state_rx.borrow() == AssocState::Closed {
+                            debug!("UDP REDIR (QUIC) {} <- {} linger window elapsed, closing", src_addr, dst_addr);
+                            break;
+                        }
                     }
                 }
-            };
-
-            // Resolved only if watcher_rx resolved
-            let _ = future::select(transfer_fut.boxed(), watcher_rx.boxed()).await;
+            }
 
-            debug!("UDP REDIR {} <- {} finished", src_addr, dst_addr);
+            debug!("UDP REDIR (QUIC) {} <- {} finished", src_addr, dst_addr);
         });
 
         Ok(UdpAssociation {
             tx,
-            watcher: close_flag,
+            watcher: Arc::new(UdpAssociationWatcher {
+                l2r_task: Some(l2r_task),
+                r2l_task: Some(r2l_task),
+                state_tx: Some(state_tx),
+                linger,
+                remote_ctx: None,
+                reverse_key: None,
+                reverse_identity: None,
+            }),
         })
     }
 
-    /// Relay packets from local to remote
-    async fn relay_l2r(
+    /// Relay packets from local to remote, through the socket shared by every
+    /// association talking to `remote_addr`
+    async fn relay_l2r_shared(
         context: &Context,
         src: &SocketAddr,
         dst: &Address,
-        remote_udp: &mut SendHalf,
+        remote_ctx: &SharedRemoteSocketContext,
+        remote_addr: SocketAddr,
         payload: &[u8],
         timeout: Duration,
         svr_cfg: &ServerConfig,
     ) -> io::Result<()> {
-        debug!("UDP REDIR {} -> {}, payload length {} bytes", src, dst, payload.len());
+        debug!(
+            "UDP REDIR {} -> {} via shared socket to {}, payload length {} bytes",
+            src,
+            dst,
+            remote_addr,
+            payload.len()
+        );
 
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
         let mut send_buf = Vec::new();
@@ -182,14 +651,10 @@ impl UdpAssociation {
         let mut encrypt_buf = BytesMut::new();
         encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
 
-        let send_len = match svr_cfg.addr() {
-            ServerAddr::SocketAddr(ref remote_addr) => {
-                try_timeout(remote_udp.send_to(&encrypt_buf[..], remote_addr), Some(timeout)).await?
-            }
-            ServerAddr::DomainName(ref dname, port) => lookup_then!(context, dname, *port, false, |addr| {
-                try_timeout(remote_udp.send_to(&encrypt_buf[..], &addr), Some(timeout)).await
-            })
-            .map(|(_, l)| l)?,
+        let send_len = {
+            // Keep the critical section small: only the actual send is under lock
+            let mut sender = remote_ctx.sender.lock().await;
+            try_timeout(sender.send_to(&encrypt_buf[..], &remote_addr), Some(timeout)).await?
         };
 
         assert_eq!(encrypt_buf.len(), send_len);
@@ -197,44 +662,184 @@ impl UdpAssociation {
         Ok(())
     }
 
-    /// Relay packets from remote to local
-    async fn relay_r2l(
+    /// Single task per server: reads every reply off the shared socket,
+    /// decrypts it, and uses the `Address` it carries to look up which
+    /// registered association's `src_addr` (and spoofing `TProxyUdpSocket`)
+    /// should receive it
+    async fn dispatch_remote_replies(
+        remote_addr: SocketAddr,
+        mut receiver: OwnedRecvHalf,
+        remote_ctx: Weak<RemoteSocketContext>,
+        assoc_map: SharedAssocMap,
+        server: SharedPlainServerStatistic,
+    ) {
+        let svr_cfg = server.server_config();
+        let context = server.context();
+
+        let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        loop {
+            let (recv_n, raw_remote_addr) = match receiver.recv_from(&mut recv_buf).await {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("shared remote socket to {} failed to receive, error: {}", remote_addr, err);
+                    break;
+                }
+            };
+
+            let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n]) {
+                Ok(Some(b)) => b,
+                Ok(None) => {
+                    error!("UDP packet too short, received length {}", recv_n);
+                    continue;
+                }
+                Err(err) => {
+                    error!("failed to decrypt packet from {}, error: {}", raw_remote_addr, err);
+                    continue;
+                }
+            };
+
+            // SERVER -> CLIENT protocol: ADDRESS + PAYLOAD
+            let mut cur = Cursor::new(decrypt_buf);
+            let remote_address = match Address::read_from(&mut cur).await {
+                Ok(a) => a,
+                Err(err) => {
+                    error!("failed to parse address from {}, error: {}", raw_remote_addr, err);
+                    continue;
+                }
+            };
+
+            let mut payload = Vec::new();
+            if let Err(err) = cur.read_to_end(&mut payload) {
+                error!("failed to read payload from {}, error: {}", raw_remote_addr, err);
+                continue;
+            }
+
+            // A dst_addr can be registered by more than one client; deliver to
+            // every one of them, since the shared socket gives us no way to
+            // tell which client the server actually meant this for.
+            //
+            // Only a weak reference is held here, so this task doesn't keep its
+            // own context (and thus itself) alive once the cache and every
+            // association have let go of it.
+            let ctx = match remote_ctx.upgrade() {
+                Some(ctx) => ctx,
+                None => break,
+            };
+            let targets: Vec<ReverseEntry> = ctx
+                .reverse
+                .lock()
+                .await
+                .values()
+                .filter(|e| Address::from(e.dst_addr) == remote_address)
+                .cloned()
+                .collect();
+            drop(ctx);
+
+            if targets.is_empty() {
+                debug!(
+                    "dropping packet from {} via {}, no association registered for {}",
+                    raw_remote_addr, remote_addr, remote_address
+                );
+                continue;
+            }
+
+            for entry in targets {
+                debug!(
+                    "UDP REDIR {} <- {} via shared socket to {}, payload length {} bytes",
+                    entry.src_addr,
+                    remote_address,
+                    remote_addr,
+                    payload.len()
+                );
+
+                let mut local_udp = entry.local_udp.lock().await;
+                if let Err(err) = local_udp.send_to(&payload, &entry.src_addr).await {
+                    error!("failed to send back to {}, error: {}", entry.src_addr, err);
+                }
+                drop(local_udp);
+
+                // Receive-only flows (one request, then a long-lived inbound
+                // stream) have nothing on the local -> remote path to keep
+                // refreshing their expiry, so replies have to do it here too.
+                let cache_key = cache_key(&entry.src_addr, &entry.dst_addr);
+                let mut amap = assoc_map.lock().await;
+                let _ = amap.get(&cache_key);
+            }
+        }
+    }
+
+    /// Relay packets from local to remote over a QUIC stream, length-prefixed
+    /// so the server-side reader can recover frame boundaries
+    async fn relay_l2r_quic(
+        context: &Context,
+        src: &SocketAddr,
+        dst: &Address,
+        quic_send: &mut quinn::SendStream,
+        payload: &[u8],
+        svr_cfg: &ServerConfig,
+    ) -> io::Result<()> {
+        debug!(
+            "UDP REDIR (QUIC) {} -> {}, payload length {} bytes",
+            src,
+            dst,
+            payload.len()
+        );
+
+        // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
+        let mut send_buf = Vec::new();
+        dst.write_to_buf(&mut send_buf);
+        send_buf.extend_from_slice(payload);
+
+        let mut encrypt_buf = BytesMut::new();
+        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+
+        quic_send.write_u32(encrypt_buf.len() as u32).await?;
+        quic_send.write_all(&encrypt_buf[..]).await?;
+
+        Ok(())
+    }
+
+    /// Relay packets from remote to local over a QUIC stream
+    async fn relay_r2l_quic(
         context: &Context,
         src_addr: &SocketAddr,
-        remote_udp: &mut RecvHalf,
+        quic_recv: &mut quinn::RecvStream,
         local_udp: &mut TProxyUdpSocket,
         svr_cfg: &ServerConfig,
     ) -> io::Result<()> {
-        // Waiting for response from server SERVER -> CLIENT
-        // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
-        let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let frame_len = quic_recv.read_u32().await? as usize;
+        if frame_len > MAXIMUM_UDP_PAYLOAD_SIZE {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "QUIC UDP frame too large");
+            return Err(err);
+        }
 
-        let (recv_n, remote_addr) = remote_udp.recv_from(&mut recv_buf).await?;
+        let mut recv_buf = vec![0u8; frame_len];
+        quic_recv.read_exact(&mut recv_buf).await?;
 
-        let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n])? {
+        let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..])? {
             None => {
-                error!("UDP packet too short, received length {}", recv_n);
+                error!("QUIC UDP frame too short, received length {}", frame_len);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
                 return Err(err);
             }
             Some(b) => b,
         };
+
         // SERVER -> CLIENT protocol: ADDRESS + PAYLOAD
         let mut cur = Cursor::new(decrypt_buf);
-        // FIXME: Address is ignored. Maybe useful in the future if we uses one common UdpSocket for communicate with remote server
-        let _ = Address::read_from(&mut cur).await?;
+        let remote_addr = Address::read_from(&mut cur).await?;
 
         let mut payload = Vec::new();
         cur.read_to_end(&mut payload)?;
 
         debug!(
-            "UDP REDIR {} <- {}, payload length {} bytes",
+            "UDP REDIR (QUIC) {} <- {}, payload length {} bytes",
             src_addr,
             remote_addr,
             payload.len()
         );
 
-        // Send back to src_addr
         local_udp.send_to(&payload, src_addr).await.map(|_| ())
     }
 
@@ -269,22 +874,64 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     info!("ShadowSocks UDP Redir listening on {}", local_addr);
 
-    // NOTE: Associations are only eliminated by expire time
-    // So it may exhaust all available file descriptors
+    // Associations are eliminated by expire time or, past MAX_ASSOCIATIONS_CAPACITY,
+    // by LRU eviction -- both paths drop UdpAssociationWatcher, which aborts the
+    // association's tasks and releases its socket / QUIC stream.
     let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
-    let assoc_map: SharedAssocMap = Arc::new(Mutex::new(LruCache::with_expiry_duration(timeout)));
+    let assoc_map: SharedAssocMap = Arc::new(Mutex::new(LruCache::with_expiry_duration_and_capacity(
+        timeout,
+        MAX_ASSOCIATIONS_CAPACITY,
+    )));
+
+    // Shared across every QUIC-transport association, regardless of which server it talks to
+    let quic_ctx = init_quic_context()?;
+
+    // Shared across every native-transport association, one real socket per server
+    let remote_socket_cache = init_remote_socket_cache();
+
+    // Broadcasts shutdown to the cleanup ticker below; flipped when the receive
+    // loop exits so the ticker doesn't outlive it
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    // Dedicated cleanup ticker, decoupled from the receive loop so expiry purging
+    // runs on a fixed cadence instead of stalling while packets keep arriving
+    {
+        let assoc_map = assoc_map.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        let mut ticker = time::interval(timeout / CLEANUP_TICK_DIVISOR);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        // Keep the critical section small: just acquire the lock long
+                        // enough to let the LRU cache evict whatever has expired
+                        let mut amap = assoc_map.lock().await;
+                        let _ = amap.iter();
+                    }
+                    _ = shutdown_rx.changed() => {
+                        if *shutdown_rx.borrow() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            debug!("UDP REDIR association cleanup ticker stopped");
+        });
+    }
 
     let mut pkt_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
     loop {
-        let (recv_len, src, dst) = match time::timeout(timeout, l.recv_from(&mut pkt_buf)).await {
-            Ok(r) => r?,
-            Err(..) => {
-                // Cleanup expired association
-                // Do not consume this iterator, it will updates expire time of items that traversed
-                let mut assoc_map = assoc_map.lock().await;
-                let _ = assoc_map.iter();
-                continue;
+        let (recv_len, src, dst) = match l.recv_from(&mut pkt_buf).await {
+            Ok(r) => r,
+            Err(err) => {
+                let _ = shutdown_tx.send(true);
+                // Spawned tasks hold their own handles to the map, so release
+                // the associations explicitly to start their close
+                assoc_map.lock().await.clear();
+                return Err(err);
             }
         };
 
@@ -311,28 +958,46 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         }
 
         // Check or (re)create an association
-        let mut assoc = {
-            // Locks the whole association map
+        let key = cache_key(&src, &dst);
+
+        let cached = {
             let mut ref_assoc_map = assoc_map.lock().await;
+            ref_assoc_map.get(&key).cloned()
+        };
 
-            // Get or create an association
-            let assoc = match ref_assoc_map.entry(cache_key(&src, &dst)) {
-                Entry::Occupied(oc) => oc.into_mut(),
-                Entry::Vacant(vc) => {
-                    // Pick a server
-                    let server = balancer.pick_server();
-
-                    vc.insert(
-                        UdpAssociation::associate(server, src, dst, assoc_map.clone())
-                            .await
-                            .expect("Failed to create udp association"),
-                    )
+        let mut assoc = match cached {
+            Some(assoc) => assoc,
+            None => {
+                // Pick a server and create the association *without* holding the
+                // map lock: associate() now does real I/O (DNS lookups, QUIC
+                // handshakes), and a single slow/failing one would otherwise
+                // block every other packet's lookup behind it.
+                let server = balancer.pick_server();
+
+                match UdpAssociation::associate(
+                    server,
+                    src,
+                    dst,
+                    assoc_map.clone(),
+                    quic_ctx.clone(),
+                    remote_socket_cache.clone(),
+                )
+                .await
+                {
+                    Ok(assoc) => {
+                        let mut ref_assoc_map = assoc_map.lock().await;
+                        ref_assoc_map.insert(key, assoc.clone());
+                        assoc
+                    }
+                    Err(err) => {
+                        // A DNS failure, a down server, or a rejected QUIC
+                        // handshake are ordinary runtime conditions, not reasons
+                        // to tear down the whole UDP relay.
+                        error!("failed to create udp association {} <-> {}, error: {}", src, dst, err);
+                        continue;
+                    }
                 }
-            };
-
-            // Clone the handle and release the lock.
-            // Make sure we keep the critical section small
-            assoc.clone()
+            }
         };
 
         // Send to local -> remote task